@@ -1,32 +1,307 @@
-use std::{collections::HashMap, convert::Infallible, error::Error, net::SocketAddr, sync::Arc};
+use std::{
+    collections::HashMap, convert::Infallible, error::Error, fs::File, io::BufReader,
+    net::SocketAddr, sync::Arc, time::Instant,
+};
 
+use async_trait::async_trait;
+use futures::stream::{self, StreamExt};
 use hyper::{
-    body::{to_bytes, Bytes},
+    body::{Bytes, HttpBody},
     client::HttpConnector,
-    header::{HeaderValue, AUTHORIZATION, CONTENT_TYPE},
+    header::{HeaderName, HeaderValue, AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE, COOKIE},
+    server::conn::Http,
     service::{make_service_fn, service_fn},
     Body, Client, HeaderMap, Request, Response, Server, Uri,
 };
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, Encoder, HistogramVec,
+    IntCounter, IntCounterVec, TextEncoder,
+};
+use tokio::net::TcpListener;
+use tokio_rustls::{
+    rustls::{self, Certificate, PrivateKey},
+    TlsAcceptor,
+};
+
+/// Default cap on how much of a request body we'll buffer in memory while
+/// looking for a legacy `auth` field. Override with `LEMMY_MAX_AUTH_BODY`.
+const DEFAULT_MAX_AUTH_BODY_BYTES: usize = 1024 * 1024; // 1 MiB
+
+/// Order in which auth sources are tried when `LEMMY_AUTH_SOURCES` isn't set
+const DEFAULT_AUTH_SOURCES: &str = "cookie,query,json,form";
 
 struct ProxyContext {
-    client: Client<HttpConnector>,
-    upstream: String,
+    client: Client<HttpsConnector<HttpConnector>>,
+    upstream: Uri,
+    auth_sources: Vec<Box<dyn AuthSource>>,
+    auth_header: AuthHeaderConfig,
+    metrics: Metrics,
+}
+
+/// A pluggable way to locate a legacy auth token within a request. A source's
+/// `label` also tags the metrics and access log lines that record whether (and
+/// how) a token was found for a given request.
+#[async_trait]
+trait AuthSource: Send + Sync {
+    /// Short label identifying this source, e.g. "cookie", "query", "json"
+    fn label(&self) -> &'static str;
+
+    /// Attempts to find a token. May consume `body` and return a replacement;
+    /// implementations that don't need to read the body must return it
+    /// unchanged.
+    async fn extract(
+        &self,
+        uri: &Uri,
+        headers: &HeaderMap,
+        body: Body,
+    ) -> Result<(Body, Option<String>), Response<Body>>;
+}
+
+/// Extracts a token from the legacy `jwt` cookie
+struct CookieAuthSource;
+
+#[async_trait]
+impl AuthSource for CookieAuthSource {
+    fn label(&self) -> &'static str {
+        "cookie"
+    }
+
+    async fn extract(
+        &self,
+        _uri: &Uri,
+        headers: &HeaderMap,
+        body: Body,
+    ) -> Result<(Body, Option<String>), Response<Body>> {
+        Ok((body, extract_auth_from_cookie(headers)))
+    }
+}
+
+/// Extracts a token from a `?auth=` query parameter
+struct QueryAuthSource;
+
+#[async_trait]
+impl AuthSource for QueryAuthSource {
+    fn label(&self) -> &'static str {
+        "query"
+    }
+
+    async fn extract(
+        &self,
+        uri: &Uri,
+        _headers: &HeaderMap,
+        body: Body,
+    ) -> Result<(Body, Option<String>), Response<Body>> {
+        Ok((body, extract_auth_from_query(uri.query())))
+    }
+}
+
+/// Extracts a token from a JSON request body's "auth" property
+struct JsonBodyAuthSource {
+    max_body_bytes: usize,
+}
+
+#[async_trait]
+impl AuthSource for JsonBodyAuthSource {
+    fn label(&self) -> &'static str {
+        "json"
+    }
+
+    async fn extract(
+        &self,
+        _uri: &Uri,
+        headers: &HeaderMap,
+        body: Body,
+    ) -> Result<(Body, Option<String>), Response<Body>> {
+        extract_auth_from_body(
+            headers,
+            body,
+            self.max_body_bytes,
+            "application/json",
+            parse_auth_from_json,
+        )
+        .await
+    }
+}
+
+/// Extracts a token from an `application/x-www-form-urlencoded` request body's
+/// `auth` field
+struct FormBodyAuthSource {
+    max_body_bytes: usize,
+}
+
+#[async_trait]
+impl AuthSource for FormBodyAuthSource {
+    fn label(&self) -> &'static str {
+        "form"
+    }
+
+    async fn extract(
+        &self,
+        _uri: &Uri,
+        headers: &HeaderMap,
+        body: Body,
+    ) -> Result<(Body, Option<String>), Response<Body>> {
+        extract_auth_from_body(
+            headers,
+            body,
+            self.max_body_bytes,
+            "application/x-www-form-urlencoded",
+            parse_auth_from_form,
+        )
+        .await
+    }
+}
+
+/// Builds the ordered list of auth sources to try, from `LEMMY_AUTH_SOURCES`
+/// (a comma-separated list of "cookie", "query", "json", "form") or
+/// [`DEFAULT_AUTH_SOURCES`] if unset
+fn build_auth_sources(max_body_bytes: usize) -> Result<Vec<Box<dyn AuthSource>>, Box<dyn Error>> {
+    let configured =
+        std::env::var("LEMMY_AUTH_SOURCES").unwrap_or_else(|_| DEFAULT_AUTH_SOURCES.to_owned());
+
+    configured
+        .split(',')
+        .map(|name| match name.trim() {
+            "cookie" => Ok(Box::new(CookieAuthSource) as Box<dyn AuthSource>),
+            "query" => Ok(Box::new(QueryAuthSource) as Box<dyn AuthSource>),
+            "json" => Ok(Box::new(JsonBodyAuthSource { max_body_bytes }) as Box<dyn AuthSource>),
+            "form" => Ok(Box::new(FormBodyAuthSource { max_body_bytes }) as Box<dyn AuthSource>),
+            other => Err(format!("Unknown LEMMY_AUTH_SOURCES entry: {}", other).into()),
+        })
+        .collect()
+}
+
+/// Configures the header auth tokens are injected as: which header, and how
+/// the token is formatted into its value (e.g. a `Bearer` scheme)
+struct AuthHeaderConfig {
+    name: HeaderName,
+    scheme: Option<String>,
+}
+
+impl AuthHeaderConfig {
+    /// Fails with a 400 response if `token` contains bytes that can't appear
+    /// in a header value (e.g. a `\r\n` smuggled through a JSON or
+    /// form-urlencoded body)
+    fn format(&self, token: &str) -> Result<HeaderValue, Response<Body>> {
+        let value = match &self.scheme {
+            Some(scheme) => format!("{} {}", scheme, token),
+            None => token.to_owned(),
+        };
+
+        HeaderValue::from_str(&value).map_err(|_| {
+            Response::builder()
+                .status(400)
+                .body(Body::from("Invalid auth token"))
+                .unwrap()
+        })
+    }
+}
+
+/// Prometheus instruments tracking proxy activity, exposed on
+/// `LEMMY_METRICS_BIND` (if set) as a text endpoint under `/metrics`
+struct Metrics {
+    requests_received: IntCounter,
+    requests_served: IntCounterVec,
+    request_proxy_duration: HistogramVec,
+}
+
+impl Metrics {
+    fn new() -> Result<Self, prometheus::Error> {
+        Ok(Self {
+            requests_received: register_int_counter!(
+                "requests_received",
+                "Total number of requests received by the proxy"
+            )?,
+            requests_served: register_int_counter_vec!(
+                "requests_served",
+                "Total number of requests forwarded upstream, by response status and auth injection source",
+                &["status", "auth_source"]
+            )?,
+            request_proxy_duration: register_histogram_vec!(
+                "request_proxy_duration_seconds",
+                "Time spent proxying a request to the upstream, in seconds, by response status and auth injection source",
+                &["status", "auth_source"]
+            )?,
+        })
+    }
 }
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<(), Box<dyn Error>> {
-    let server_addr = SocketAddr::from(([127, 0, 0, 1], 8536));
+    let bind_addr = match std::env::var("LEMMY_BIND") {
+        Ok(value) => value.parse()?,
+        Err(_) => SocketAddr::from(([127, 0, 0, 1], 8536)),
+    };
 
-    let upstream = match std::env::var("LEMMY_UPSTREAM") {
+    let upstream: Uri = match std::env::var("LEMMY_UPSTREAM") {
         Ok(value) => Ok(value),
         Err(_) => Err("Missing LEMMY_UPSTREAM value"),
-    }?;
+    }?
+    .parse()?;
+
+    if upstream.scheme().is_none() || upstream.authority().is_none() {
+        return Err("LEMMY_UPSTREAM must be a full URL, including scheme and host".into());
+    }
+
+    let max_auth_body_bytes = std::env::var("LEMMY_MAX_AUTH_BODY")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_MAX_AUTH_BODY_BYTES);
+
+    let auth_sources = build_auth_sources(max_auth_body_bytes)?;
+
+    let auth_header = AuthHeaderConfig {
+        name: match std::env::var("LEMMY_AUTH_HEADER_NAME") {
+            Ok(value) => HeaderName::from_bytes(value.as_bytes())?,
+            Err(_) => AUTHORIZATION,
+        },
+        scheme: match std::env::var("LEMMY_AUTH_HEADER_SCHEME") {
+            Ok(value) if value.is_empty() => None,
+            Ok(value) => Some(value),
+            Err(_) => Some("Bearer".to_owned()),
+        },
+    };
+
+    let https = HttpsConnectorBuilder::new()
+        .with_native_roots()
+        .https_or_http()
+        .enable_http1()
+        .build();
 
     let context = Arc::new(ProxyContext {
-        client: Client::new(),
+        client: Client::builder().build(https),
         upstream,
+        auth_sources,
+        auth_header,
+        metrics: Metrics::new()?,
     });
 
+    if let Ok(metrics_bind) = std::env::var("LEMMY_METRICS_BIND") {
+        let metrics_addr: SocketAddr = metrics_bind.parse()?;
+        tokio::spawn(async move {
+            if let Err(e) = serve_metrics(metrics_addr).await {
+                eprintln!("Metrics server error: {}", e);
+            }
+        });
+    }
+
+    let tls_config = match (
+        std::env::var("LEMMY_TLS_CERT"),
+        std::env::var("LEMMY_TLS_KEY"),
+    ) {
+        (Ok(cert_path), Ok(key_path)) => Some(load_tls_config(&cert_path, &key_path)?),
+        _ => None,
+    };
+
+    match tls_config {
+        Some(tls_config) => serve_tls(bind_addr, tls_config, context).await,
+        None => serve_plain(bind_addr, context).await,
+    }
+}
+
+/// Serves plain HTTP, as before TLS support existed
+async fn serve_plain(addr: SocketAddr, context: Arc<ProxyContext>) -> Result<(), Box<dyn Error>> {
     let make_service = make_service_fn(|_conn| {
         let context = context.clone();
         let service = service_fn(move |req| proxy_request(context.clone(), req));
@@ -34,88 +309,244 @@ async fn main() -> Result<(), Box<dyn Error>> {
         async move { Ok::<_, Infallible>(service) }
     });
 
-    let server = Server::bind(&server_addr).serve(make_service);
-
-    if let Err(e) = server.await {
+    if let Err(e) = Server::bind(&addr).serve(make_service).await {
         eprintln!("Server error: {}", e);
     }
 
     Ok(())
 }
 
+/// Serves HTTPS by terminating TLS on each accepted connection with rustls
+/// before handing it to the same proxy pipeline used for plain HTTP
+async fn serve_tls(
+    addr: SocketAddr,
+    tls_config: Arc<rustls::ServerConfig>,
+    context: Arc<ProxyContext>,
+) -> Result<(), Box<dyn Error>> {
+    let listener = TcpListener::bind(addr).await?;
+    let acceptor = TlsAcceptor::from(tls_config);
+
+    loop {
+        let (stream, _) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                eprintln!("Accept failed: {}", e);
+                continue;
+            }
+        };
+        let acceptor = acceptor.clone();
+        let context = context.clone();
+
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("TLS handshake failed: {}", e);
+                    return;
+                }
+            };
+
+            let service = service_fn(move |req| proxy_request(context.clone(), req));
+
+            if let Err(e) = Http::new().serve_connection(stream, service).await {
+                eprintln!("Connection error: {}", e);
+            }
+        });
+    }
+}
+
+/// Serves the Prometheus text exposition format for all registered metrics
+/// under `/metrics`
+async fn serve_metrics(addr: SocketAddr) -> Result<(), Box<dyn Error>> {
+    let make_service = make_service_fn(|_conn| async {
+        Ok::<_, Infallible>(service_fn(|_req: Request<Body>| async {
+            let metric_families = prometheus::gather();
+            let mut buffer = Vec::new();
+
+            TextEncoder::new()
+                .encode(&metric_families, &mut buffer)
+                .unwrap();
+
+            Ok::<_, Infallible>(Response::new(Body::from(buffer)))
+        }))
+    });
+
+    if let Err(e) = Server::bind(&addr).serve(make_service).await {
+        eprintln!("Metrics server error: {}", e);
+    }
+
+    Ok(())
+}
+
+/// Loads a `rustls::ServerConfig` from a PEM certificate chain and private key
+fn load_tls_config(
+    cert_path: &str,
+    key_path: &str,
+) -> Result<Arc<rustls::ServerConfig>, Box<dyn Error>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path)?))?
+        .into_iter()
+        .map(Certificate)
+        .collect::<Vec<_>>();
+
+    // read_one already understands PKCS#1 (RSA), PKCS#8 and SEC1 (EC) keys,
+    // so a single pass over the file picks up whichever format the operator's
+    // cert tooling produced
+    let mut key_reader = BufReader::new(File::open(key_path)?);
+    let key = loop {
+        match rustls_pemfile::read_one(&mut key_reader)? {
+            Some(rustls_pemfile::Item::RSAKey(key)) => break Some(key),
+            Some(rustls_pemfile::Item::PKCS8Key(key)) => break Some(key),
+            Some(rustls_pemfile::Item::ECKey(key)) => break Some(key),
+            Some(_) => continue,
+            None => break None,
+        }
+    };
+
+    let key = key
+        .map(PrivateKey)
+        .ok_or("No private key found in LEMMY_TLS_KEY file")?;
+
+    let config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+
+    Ok(Arc::new(config))
+}
+
 /// Proxies an incoming request to the Lemmy backend, rewriting any legacy auth
 /// parameter to an Authorization header
 async fn proxy_request<'a>(
     context: Arc<ProxyContext>,
     incoming_request: Request<Body>,
 ) -> Result<Response<Body>, Infallible> {
+    context.metrics.requests_received.inc();
+
+    let start = Instant::now();
+    let method = incoming_request.method().clone();
+    let path = incoming_request.uri().path().to_owned();
+
     let (incoming_parts, incoming_body) = incoming_request.into_parts();
     let (incoming_headers, incoming_uri) = (incoming_parts.headers, incoming_parts.uri);
 
-    let (proxy_headers, proxy_body) =
-        match try_inject_auth_header(&incoming_uri, &incoming_headers, incoming_body).await {
-            Ok(result) => result,
-            Err(err_resp) => return Ok(err_resp),
-        };
+    // Tracks whichever auth source (if any) actually supplied a token, for
+    // metrics/the access log below; stays "none" if auth injection fails with
+    // an error response
+    let mut auth_source = "none";
+    // Whether the request was actually sent to the upstream
+    let mut forwarded = false;
 
-    let mut proxy_request = Request::builder()
-        .uri(
-            Uri::builder()
-                .scheme("http")
-                .authority(context.upstream.clone())
-                .path_and_query(incoming_uri.path_and_query().unwrap().as_str())
-                .build()
-                .unwrap(),
-        )
-        .method(incoming_parts.method)
-        .body(proxy_body)
-        .unwrap();
+    let response = match try_inject_auth_header(
+        &incoming_uri,
+        &incoming_headers,
+        incoming_body,
+        &context.auth_sources,
+        &context.auth_header,
+    )
+    .await
+    {
+        Ok((proxy_headers, proxy_body, source)) => {
+            auth_source = source;
 
-    *proxy_request.headers_mut() = proxy_headers;
+            let mut proxy_request = Request::builder()
+                .uri(
+                    Uri::builder()
+                        .scheme(context.upstream.scheme().unwrap().clone())
+                        .authority(context.upstream.authority().unwrap().clone())
+                        .path_and_query(incoming_uri.path_and_query().unwrap().as_str())
+                        .build()
+                        .unwrap(),
+                )
+                .method(incoming_parts.method)
+                .body(proxy_body)
+                .unwrap();
 
-    let proxy_response = context.client.request(proxy_request).await;
+            *proxy_request.headers_mut() = proxy_headers;
 
-    Ok(match proxy_response {
-        Ok(response) => response,
-        Err(e) => Response::builder()
-            .status(502)
-            .body(Body::from(format!("Upstream failed to respond: {}", e)))
-            .unwrap(),
-    })
+            forwarded = true;
+            match context.client.request(proxy_request).await {
+                Ok(response) => response,
+                Err(e) => Response::builder()
+                    .status(502)
+                    .body(Body::from(format!("Upstream failed to respond: {}", e)))
+                    .unwrap(),
+            }
+        }
+        Err(err_resp) => err_resp,
+    };
+
+    let status = response.status().as_str().to_owned();
+    let elapsed = start.elapsed();
+
+    if forwarded {
+        context
+            .metrics
+            .requests_served
+            .with_label_values(&[&status, auth_source])
+            .inc();
+        context
+            .metrics
+            .request_proxy_duration
+            .with_label_values(&[&status, auth_source])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    println!(
+        "method={method} path={path} status={status} latency_ms={elapsed_ms:.3} auth={auth_source}",
+        elapsed_ms = elapsed.as_secs_f64() * 1000.0,
+    );
+
+    Ok(response)
 }
 
-/// Attempts to convert a GET ?auth= query parameter or a JSON body "auth"
-/// property to an Authorization header.
+/// Attempts to convert a token found by one of `auth_sources` (tried in
+/// order, first hit wins) into an injected auth header.
 ///
-/// May consume the request body and return a new one, but will return the same
-/// unprocessed body if possible
+/// May consume the request body and return a new one, but will return the
+/// same unprocessed body if possible. Returns the label of whichever source
+/// (if any) supplied a token, for metrics/access-log tagging.
 async fn try_inject_auth_header(
     uri: &Uri,
     headers: &HeaderMap,
     body: Body,
-) -> Result<(HeaderMap, Body), Response<Body>> {
+    auth_sources: &[Box<dyn AuthSource>],
+    auth_header: &AuthHeaderConfig,
+) -> Result<(HeaderMap, Body, &'static str), Response<Body>> {
     let mut proxy_headers = headers.clone();
 
-    // Do nothing in presence of existing authorization header
-    if headers.contains_key(AUTHORIZATION) {
-        Ok((proxy_headers, body))
-    }
-    // If we can find auth in the query string, use that
-    else if let Some(auth) = extract_auth_from_query(uri.query()) {
-        // We got a ?auth= parameter, no need to parse body
-        proxy_headers.append(AUTHORIZATION, auth_token_to_bearer(&auth));
-        Ok((proxy_headers, body))
+    // Do nothing if the configured output header is already present
+    if headers.contains_key(&auth_header.name) {
+        return Ok((proxy_headers, body, "none"));
     }
-    // Otherwise, attempt to match an auth param in the body
-    else {
-        let (body, auth) = try_extract_auth_from_body(headers, body).await?;
+
+    let mut body = body;
+
+    for source in auth_sources {
+        let (new_body, auth) = source.extract(uri, headers, body).await?;
+        body = new_body;
 
         if let Some(auth) = auth {
-            proxy_headers.append(AUTHORIZATION, auth_token_to_bearer(&auth));
+            proxy_headers.append(auth_header.name.clone(), auth_header.format(&auth)?);
+            return Ok((proxy_headers, body, source.label()));
         }
-
-        Ok((proxy_headers, body))
     }
+
+    Ok((proxy_headers, body, "none"))
+}
+
+/// Attempts to extract the legacy `jwt` cookie set by older Lemmy web clients
+fn extract_auth_from_cookie(headers: &HeaderMap) -> Option<String> {
+    let cookie_header = headers.get(COOKIE)?.to_str().ok()?;
+
+    cookie_header.split(';').find_map(|pair| {
+        let (name, value) = pair.split_once('=')?;
+
+        if name.trim() == "jwt" {
+            Some(value.trim().to_owned())
+        } else {
+            None
+        }
+    })
 }
 
 /// Attempts to extract a ?auth= query paramter
@@ -136,52 +567,105 @@ fn extract_auth_from_query(query: Option<&str>) -> Option<String> {
     }
 }
 
-/// Attempts to extract an "auth" property from a JSON body
+/// Attempts to extract an auth token from a request body, shared by the
+/// content-type-gated body auth sources.
+///
+/// Only buffers the body if its Content-Type contains `expected_content_type`;
+/// otherwise returns it unchanged. May fail and return an error response if
+/// it does so. If the body merely doesn't parse, the body is reconstructed
+/// and no token is returned.
 ///
-/// Will consume the body if the content-type is application/json. It may fail
-/// and return an error response if it does so. If the body merely doesn't parse
-/// as JSON, then the body is reconstructed and no authorization header is
-/// returned.
-async fn try_extract_auth_from_body(
+/// If the body turns out to be larger than `max_body_bytes`, it is never
+/// fully buffered: it is forwarded upstream unmodified and no token is
+/// extracted.
+async fn extract_auth_from_body(
     headers: &HeaderMap,
     body: Body,
+    max_body_bytes: usize,
+    expected_content_type: &str,
+    parse_auth: fn(&[u8]) -> Option<String>,
 ) -> Result<(Body, Option<String>), Response<Body>> {
-    // If not application/json, don't waste our time
-    if !headers.get(CONTENT_TYPE).map_or(false, |h| {
-        h.to_str().unwrap_or("").contains("application/json")
-    }) {
+    let content_type = headers
+        .get(CONTENT_TYPE)
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or("");
+
+    if !content_type.contains(expected_content_type) {
         return Ok((body, Option::None));
     }
 
-    let data = body_to_bytes(body).await?;
+    match buffer_body_bounded(headers, body, max_body_bytes).await? {
+        BufferedBody::TooLarge(body) => Ok((body, Option::None)),
+        BufferedBody::Complete(data) => {
+            let auth = parse_auth(&data);
+            Ok((Body::from(data), auth))
+        }
+    }
+}
 
-    let auth = match String::from_utf8(data.to_vec()) {
-        Ok(data) => match json::parse(&data) {
-            Ok(data) => data["auth"].as_str().map(|v| v.to_owned()),
-            _ => Option::None, // No auth if we can't parse as JSON
-        },
-        _ => Option::None, // No auth if we can't parse as UTF-8
-    };
+/// Extracts the "auth" property from a JSON document, if present and valid
+fn parse_auth_from_json(data: &[u8]) -> Option<String> {
+    let data = std::str::from_utf8(data).ok()?;
+    let data = json::parse(data).ok()?;
+    data["auth"].as_str().map(|v| v.to_owned())
+}
 
-    Ok((Body::from(data), auth))
+/// Extracts the `auth` field from a form-urlencoded body
+fn parse_auth_from_form(data: &[u8]) -> Option<String> {
+    let form: HashMap<String, String> = url::form_urlencoded::parse(data).into_owned().collect();
+    form.get("auth").cloned()
 }
 
-/// Converts a plain auth token to a Bearer token header value
-fn auth_token_to_bearer(auth: &str) -> HeaderValue {
-    let h = format!("Bearer {}", auth);
-    HeaderValue::from_str(&h).unwrap()
+/// Outcome of [`buffer_body_bounded`]
+enum BufferedBody {
+    /// The whole body was read and fit under the limit
+    Complete(Bytes),
+    /// The body exceeded the limit; here's a body that replays it unmodified
+    TooLarge(Body),
 }
 
-/// Converts a body to a Bytes
+/// Reads `body` into memory up to `limit` bytes, never holding more than
+/// `limit` bytes plus the most recently received chunk in RAM at once.
 ///
-/// Returns an error response in case of error reading the body
-async fn body_to_bytes(body: Body) -> Result<Bytes, Response<Body>> {
-    if let Ok(body) = to_bytes(body).await {
-        Ok(body)
-    } else {
-        Err(Response::builder()
-            .status(400)
-            .body(Body::from("Failed to receive request body"))
-            .unwrap())
+/// If `Content-Length` is present and already over `limit`, or the streamed
+/// byte count crosses `limit` while accumulating, buffering stops immediately
+/// and a `Body` replaying whatever was already read, followed by the
+/// remainder of the stream, is returned so the request can still be proxied
+/// unmodified.
+async fn buffer_body_bounded(
+    headers: &HeaderMap,
+    mut body: Body,
+    limit: usize,
+) -> Result<BufferedBody, Response<Body>> {
+    let declared_too_large = headers
+        .get(CONTENT_LENGTH)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.parse::<usize>().ok())
+        .map_or(false, |len| len > limit);
+
+    if declared_too_large {
+        return Ok(BufferedBody::TooLarge(body));
+    }
+
+    let mut buf = Vec::new();
+
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|_| {
+            Response::builder()
+                .status(400)
+                .body(Body::from("Failed to receive request body"))
+                .unwrap()
+        })?;
+
+        buf.extend_from_slice(&chunk);
+
+        if buf.len() > limit {
+            let already_read = stream::once(async move { Ok::<_, hyper::Error>(Bytes::from(buf)) });
+            return Ok(BufferedBody::TooLarge(Body::wrap_stream(
+                already_read.chain(body),
+            )));
+        }
     }
+
+    Ok(BufferedBody::Complete(Bytes::from(buf)))
 }